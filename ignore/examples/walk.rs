@@ -12,7 +12,7 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
 
 use crossbeam::sync::MsQueue;
-use ignore::WalkBuilder;
+use ignore::{WalkBuilder, WalkState};
 use walkdir::WalkDir;
 
 fn main() {
@@ -47,6 +47,7 @@ fn main() {
             // let stdout = io::stdout();
             // let mut stdout = stdout.lock();
             // write_path(&mut stdout, result.unwrap().path());
+            WalkState::Continue
         });
     } else if simple {
         let mut stdout = io::BufWriter::new(io::stdout());
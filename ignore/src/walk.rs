@@ -1,13 +1,23 @@
+use std::any::Any;
+use std::cmp;
+use std::collections::{BinaryHeap, HashSet, VecDeque};
 use std::ffi::OsStr;
 use std::fs::{self, FileType, Metadata};
 use std::io;
+use std::mem;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::process::Command;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread;
+use std::time::Duration;
 use std::vec;
 
 use crossbeam::sync::MsQueue;
+#[cfg(feature = "stream")]
+use futures::{Async, Poll, Sink, Stream};
+#[cfg(feature = "stream")]
+use futures::sync::mpsc;
 use walkdir::{self, WalkDir, WalkDirIterator};
 
 use dir::{Ignore, IgnoreBuilder};
@@ -24,6 +34,7 @@ use {Error, PartialErrorBuilder};
 pub struct DirEntry {
     dent: DirEntryInner,
     err: Option<Error>,
+    descend: bool,
 }
 
 impl DirEntry {
@@ -56,6 +67,20 @@ impl DirEntry {
         self.dent.file_type()
     }
 
+    /// Return the device id this entry resides on, when it's already
+    /// known (cheaply, without an extra stat). Used by
+    /// `WalkBuilder::same_file_system` to prune directories without
+    /// re-fetching metadata that's already been read once.
+    fn device(&self) -> Option<u64> {
+        self.dent.device()
+    }
+
+    /// The `(device, inode)` pair identifying this entry's directory, when
+    /// it's already known. Used by `WalkParallel`'s symlink loop detection.
+    fn dir_id(&self) -> Option<(u64, u64)> {
+        self.dent.dir_id()
+    }
+
     /// Return the file name of this entry.
     ///
     /// If this entry has no file name (e.g., `/`), then the full path is
@@ -81,6 +106,7 @@ impl DirEntry {
         DirEntry {
             dent: DirEntryInner::Stdin,
             err: None,
+            descend: true,
         }
     }
 
@@ -88,6 +114,7 @@ impl DirEntry {
         DirEntry {
             dent: DirEntryInner::Walkdir(dent),
             err: err,
+            descend: true,
         }
     }
 
@@ -95,8 +122,27 @@ impl DirEntry {
         DirEntry {
             dent: DirEntryInner::Raw(dent),
             err: err,
+            descend: true,
         }
     }
+
+    /// Mark this entry to be yielded without descending into it, even
+    /// though it's a directory.
+    ///
+    /// Has no effect outside of a `WalkBuilder::process_read_dir` hook,
+    /// and no effect at all unless this entry is a directory: the walk
+    /// already doesn't descend into anything else.
+    pub fn skip_descend(&mut self) {
+        self.descend = false;
+    }
+
+    /// Whether `WalkParallel` should still queue this directory's own
+    /// children for reading. Always `true` unless `skip_descend` was
+    /// called on it, either by a `WalkBuilder::process_read_dir` hook or
+    /// by `same_file_system` pruning at a mount boundary.
+    fn should_descend(&self) -> bool {
+        self.descend
+    }
 }
 
 /// DirEntryInner is the implementation of DirEntry.
@@ -159,6 +205,30 @@ impl DirEntryInner {
         }
     }
 
+    fn device(&self) -> Option<u64> {
+        use self::DirEntryInner::*;
+        match *self {
+            Stdin => None,
+            // Not needed: `Walk`'s `same_file_system` support stats paths
+            // directly instead, since walkdir entries don't carry a
+            // cached device id.
+            Walkdir(_) => None,
+            Raw(ref x) => x.device(),
+        }
+    }
+
+    fn dir_id(&self) -> Option<(u64, u64)> {
+        use self::DirEntryInner::*;
+        match *self {
+            Stdin => None,
+            // Not needed: `Walk` relies on walkdir's own loop detection
+            // instead of the ancestor-chain bookkeeping `WalkParallel`
+            // does here.
+            Walkdir(_) => None,
+            Raw(ref x) => x.dir_id(),
+        }
+    }
+
     fn file_type(&self) -> Option<FileType> {
         use self::DirEntryInner::*;
         match *self {
@@ -201,6 +271,13 @@ struct DirEntryRaw {
     follow_link: bool,
     /// The depth at which this entry was generated relative to the root.
     depth: usize,
+    /// The device id of the file system this entry resides on, when known.
+    /// Used to implement `WalkBuilder::same_file_system`.
+    device: Option<u64>,
+    /// The inode number of this entry, when known. Paired with `device` to
+    /// uniquely identify a directory for symlink loop detection; see
+    /// `WalkParallel`'s ancestor-chain bookkeeping.
+    ino: Option<u64>,
 }
 
 impl DirEntryRaw {
@@ -232,27 +309,73 @@ impl DirEntryRaw {
         self.depth
     }
 
+    /// The device id of the file system this entry resides on, if it's
+    /// known. `from_link` and `from_path` always populate it; `from_entry`
+    /// only does when `same_file_system` or `follow_links` is set.
+    fn device(&self) -> Option<u64> {
+        self.device
+    }
+
+    /// The `(device, inode)` pair that uniquely identifies this entry's
+    /// directory, if both halves are known. `from_link` and `from_path`
+    /// always populate both; `from_entry` only does when `follow_links`
+    /// is set.
+    fn dir_id(&self) -> Option<(u64, u64)> {
+        match (self.device, self.ino) {
+            (Some(dev), Some(ino)) => Some((dev, ino)),
+            _ => None,
+        }
+    }
+
+    /// `same_file_system`/`follow_links` determine whether the device id
+    /// and inode number are worth paying a `metadata` call for: unlike
+    /// `from_link` and `from_path`, this constructor has no metadata of
+    /// its own to reuse, so the cost is only incurred when the caller
+    /// actually needs one of them. `follow_links` alone is enough to pay
+    /// for the device id as well as the inode number, since `dir_id`
+    /// needs both halves of the pair to identify a directory for loop
+    /// detection.
     fn from_entry(
         depth: usize,
-        ent: &fs::DirEntry,
+        ent: &FileSystemEntry,
+        fs: &FileSystem,
+        same_file_system: bool,
+        follow_links: bool,
     ) -> Result<DirEntryRaw, Error> {
-        let ty = try!(ent.file_type().map_err(|err| {
-            let err = Error::Io(io::Error::from(err)).with_path(ent.path());
-            Error::WithDepth {
-                depth: depth,
-                err: Box::new(err),
-            }
-        }));
+        let ty = ent.file_type();
+        let md = if same_file_system || follow_links {
+            fs.symlink_metadata(ent.path()).ok()
+        } else {
+            None
+        };
+        // `dir_id` (used for symlink loop detection) needs both halves of
+        // the pair, so `device` is computed whenever `follow_links` is on
+        // even if `same_file_system` isn't.
+        let device =
+            if same_file_system || follow_links {
+                md.as_ref().and_then(device_id)
+            } else {
+                None
+            };
+        let ino =
+            if follow_links { md.as_ref().and_then(inode_id) }
+            else { None };
         Ok(DirEntryRaw {
-            path: ent.path(),
+            path: ent.path().to_path_buf(),
             ty: ty,
             follow_link: false,
             depth: depth,
+            device: device,
+            ino: ino,
         })
     }
 
-    fn from_link(depth: usize, pb: PathBuf) -> Result<DirEntryRaw, Error> {
-        let md = try!(fs::metadata(&pb).map_err(|err| {
+    fn from_link(
+        depth: usize,
+        pb: PathBuf,
+        fs: &FileSystem,
+    ) -> Result<DirEntryRaw, Error> {
+        let md = try!(fs.metadata(&pb).map_err(|err| {
             Error::Io(err).with_path(&pb)
         }));
         Ok(DirEntryRaw {
@@ -260,11 +383,17 @@ impl DirEntryRaw {
             ty: md.file_type(),
             follow_link: true,
             depth: depth,
+            device: device_id(&md),
+            ino: inode_id(&md),
         })
     }
 
-    fn from_path(depth: usize, pb: PathBuf) -> Result<DirEntryRaw, Error> {
-        let md = try!(fs::symlink_metadata(&pb).map_err(|err| {
+    fn from_path(
+        depth: usize,
+        pb: PathBuf,
+        fs: &FileSystem,
+    ) -> Result<DirEntryRaw, Error> {
+        let md = try!(fs.symlink_metadata(&pb).map_err(|err| {
             Error::Io(err).with_path(&pb)
         }));
         Ok(DirEntryRaw {
@@ -272,10 +401,214 @@ impl DirEntryRaw {
             ty: md.file_type(),
             follow_link: false,
             depth: depth,
+            device: device_id(&md),
+            ino: inode_id(&md),
         })
     }
 }
 
+/// Abstracts where directory listings and file metadata come from, so
+/// `build_parallel`'s traversal doesn't have to hit the real file system
+/// directly.
+///
+/// `OsFileSystem` is the default implementation and preserves today's
+/// behavior exactly. See `WalkBuilder::filesystem` for why you'd swap in
+/// another one.
+pub trait FileSystem: Send + Sync {
+    /// Read the immediate children of `path`, in whatever order the
+    /// implementation finds natural; the walker sorts them itself when a
+    /// deterministic order is requested.
+    fn read_dir(
+        &self,
+        path: &Path,
+    ) -> io::Result<Box<Iterator<Item = io::Result<FileSystemEntry>>>>;
+
+    /// Equivalent to `std::fs::metadata`: follows symbolic links.
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Equivalent to `std::fs::symlink_metadata`: does not follow
+    /// symbolic links.
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata>;
+}
+
+/// One entry produced by `FileSystem::read_dir`.
+///
+/// This mirrors the subset of `std::fs::DirEntry` the walker actually
+/// needs, rather than the walker's own internal `DirEntryRaw`: letting a
+/// `FileSystem` hand back a bare path and file type (instead of having to
+/// also fill in depth, device ids and other walk-bookkeeping fields) keeps
+/// custom implementations simple to write.
+pub struct FileSystemEntry {
+    path: PathBuf,
+    file_type: FileType,
+}
+
+impl FileSystemEntry {
+    /// Create an entry for `path` with the given file type.
+    pub fn new(path: PathBuf, file_type: FileType) -> FileSystemEntry {
+        FileSystemEntry { path: path, file_type: file_type }
+    }
+
+    /// The full path of this entry.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The file type of this entry, as reported by the directory listing
+    /// (i.e., without following a symbolic link).
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    fn file_name(&self) -> &OsStr {
+        self.path.file_name().unwrap_or_else(|| self.path.as_os_str())
+    }
+}
+
+/// The default `FileSystem`, backed directly by `std::fs`.
+///
+/// This is what every walk used before `WalkBuilder::filesystem` existed,
+/// and it's still what every walk gets unless `filesystem` is called.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OsFileSystem;
+
+impl FileSystem for OsFileSystem {
+    fn read_dir(
+        &self,
+        path: &Path,
+    ) -> io::Result<Box<Iterator<Item = io::Result<FileSystemEntry>>>> {
+        let rd = try!(fs::read_dir(path));
+        let it = rd.map(|result| {
+            result.and_then(|ent| {
+                let file_type = try!(ent.file_type());
+                Ok(FileSystemEntry::new(ent.path(), file_type))
+            })
+        });
+        Ok(Box::new(it))
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::metadata(path)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::symlink_metadata(path)
+    }
+}
+
+/// The set of paths (and their containing directories) that `git diff`
+/// reports as added or modified relative to some revision.
+///
+/// This is computed once, up front, and then used to prune the walk: a
+/// directory is only descended into if it (transitively) contains a
+/// modified file, so the cost stays proportional to the size of the diff
+/// rather than the size of the repository.
+#[derive(Debug)]
+struct GitModified {
+    /// Paths to modified files, rooted the same way `ent.path()` is
+    /// during the walk (i.e. joined onto whatever root the walk was
+    /// given, which may be relative).
+    files: HashSet<PathBuf>,
+    /// Paths to directories that (transitively) contain a modified file,
+    /// rooted the same way as `files`.
+    dirs: HashSet<PathBuf>,
+}
+
+impl GitModified {
+    /// Compute the modified-path set for the repository containing `root`,
+    /// relative to `rev` (or the merge-base with the upstream of `HEAD`
+    /// if `rev` is `None`).
+    ///
+    /// Note that this only sees `git diff`'s notion of "modified", which
+    /// excludes untracked files: a brand new file that hasn't been `git
+    /// add`ed yet won't show up here, and so won't be walked.
+    fn compute(root: &Path, rev: Option<&str>) -> io::Result<GitModified> {
+        let rev = match rev {
+            Some(rev) => rev.to_string(),
+            None => {
+                let out = Command::new("git")
+                    .arg("merge-base").arg("HEAD").arg("@{u}")
+                    .current_dir(root)
+                    .output()?;
+                String::from_utf8_lossy(&out.stdout).trim().to_string()
+            }
+        };
+        let out = Command::new("git")
+            .arg("diff").arg("--name-only").arg(&rev)
+            .current_dir(root)
+            .output()?;
+        if !out.status.success() {
+            return Err(io::Error::new(io::ErrorKind::Other, "git diff failed"));
+        }
+        // `git diff --name-only` reports paths relative to the repository's
+        // toplevel, not relative to `root`, so they can't be joined onto
+        // `root` directly: `root` may be a subdirectory of the repository,
+        // and may itself be relative (e.g. `.`), neither of which `git`
+        // knows anything about. Instead, work out `root`'s own location
+        // relative to the toplevel, strip that prefix from each reported
+        // path, and join what's left onto `root` as given. That produces
+        // exactly the paths `ent.path()` will have during the walk, since
+        // the walker builds every entry's path the same way: by joining
+        // onto the (possibly relative) root it was given, never by
+        // re-rooting to an absolute path of its own.
+        let toplevel_out = Command::new("git")
+            .arg("rev-parse").arg("--show-toplevel")
+            .current_dir(root)
+            .output()?;
+        let toplevel =
+            Path::new(String::from_utf8_lossy(&toplevel_out.stdout).trim())
+            .to_path_buf();
+        let root_abs = root.canonicalize()?;
+        let root_rel_to_toplevel = root_abs.strip_prefix(&toplevel)
+            .unwrap_or(Path::new("")).to_path_buf();
+
+        let mut files = HashSet::new();
+        let mut dirs = HashSet::new();
+        for line in String::from_utf8_lossy(&out.stdout).lines() {
+            if line.is_empty() {
+                continue;
+            }
+            // Lines outside `root`'s own slice of the repository aren't
+            // reachable from this walk no matter how they're joined, so
+            // just skip them instead of recording a path that can never
+            // be compared against anything `ent.path()` produces.
+            let relative_to_root = match Path::new(line)
+                .strip_prefix(&root_rel_to_toplevel)
+            {
+                Ok(p) => p,
+                Err(_) => continue,
+            };
+            let path = root.join(relative_to_root);
+            let mut parent = path.parent();
+            while let Some(dir) = parent {
+                if !dirs.insert(dir.to_path_buf()) {
+                    // Already inserted, so all of its ancestors must have
+                    // been too.
+                    break;
+                }
+                parent = dir.parent();
+            }
+            files.insert(path);
+        }
+        Ok(GitModified { files: files, dirs: dirs })
+    }
+
+    /// Whether a directory should be descended into, i.e., whether it is
+    /// the root or it (transitively) contains a modified file.
+    fn should_descend(&self, path: &Path) -> bool {
+        self.dirs.contains(path)
+    }
+
+    /// Whether this path should be yielded to the caller.
+    fn should_yield(&self, path: &Path, is_dir: bool) -> bool {
+        if is_dir {
+            self.dirs.contains(path)
+        } else {
+            self.files.contains(path)
+        }
+    }
+}
+
 /// WalkBuilder builds a recursive directory iterator.
 ///
 /// The builder supports a large number of configurable options. This includes
@@ -322,14 +655,96 @@ impl DirEntryRaw {
 /// path is skipped.
 /// * Sixth, if the path has made it this far then it is yielded in the
 /// iterator.
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct WalkBuilder {
     paths: Vec<PathBuf>,
     ig_builder: IgnoreBuilder,
     parents: bool,
     max_depth: Option<usize>,
+    max_filesize: Option<u64>,
     follow_links: bool,
+    same_file_system: bool,
     threads: usize,
+    sorter: Option<Sorter>,
+    ordered: bool,
+    git_modified_rev: Option<Option<String>>,
+    filter: Option<EntryFilter>,
+    read_dir_hook: Option<ReadDirHook>,
+    fs: Arc<FileSystem>,
+}
+
+/// A predicate used to prune entries from a walk after ignore/override/
+/// type matching has run, but before an entry is yielded. See
+/// `WalkBuilder::filter_entry`.
+type EntryFilter = Arc<Fn(&DirEntry) -> bool + Send + Sync + 'static>;
+
+/// Per-directory client state threaded down `build_parallel`'s tree by a
+/// `WalkBuilder::process_read_dir` hook. Type-erased so `WalkBuilder`
+/// itself doesn't need to be generic over it; the hook registered in
+/// `process_read_dir` closes over the caller's concrete `T` and downcasts
+/// back to it on every call.
+type ReadDirState = Box<Any + Send>;
+
+/// See `WalkBuilder::process_read_dir`.
+#[derive(Clone)]
+struct ReadDirHook {
+    call: Arc<
+        Fn(usize, &Path, &mut Vec<DirEntry>, &mut ReadDirState)
+        + Send + Sync + 'static
+    >,
+    default: Arc<Fn() -> ReadDirState + Send + Sync + 'static>,
+    clone_state: Arc<Fn(&ReadDirState) -> ReadDirState + Send + Sync + 'static>,
+}
+
+impl ::std::fmt::Debug for WalkBuilder {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("WalkBuilder")
+            .field("paths", &self.paths)
+            .field("ig_builder", &self.ig_builder)
+            .field("parents", &self.parents)
+            .field("max_depth", &self.max_depth)
+            .field("max_filesize", &self.max_filesize)
+            .field("follow_links", &self.follow_links)
+            .field("same_file_system", &self.same_file_system)
+            .field("threads", &self.threads)
+            .field("sorter", &self.sorter.is_some())
+            .field("ordered", &self.ordered)
+            .field("git_modified_rev", &self.git_modified_rev)
+            .field("filter", &self.filter.is_some())
+            .field("read_dir_hook", &self.read_dir_hook.is_some())
+            .field("fs", &"<filesystem>")
+            .finish()
+    }
+}
+
+/// A comparator used to sort file names relative to one another while
+/// traversing a directory.
+type FileNameCmp =
+    Arc<Fn(&OsStr, &OsStr) -> cmp::Ordering + Send + Sync + 'static>;
+
+/// A comparator used to sort full paths relative to one another while
+/// traversing a directory.
+type PathCmp = Arc<Fn(&Path, &Path) -> cmp::Ordering + Send + Sync + 'static>;
+
+/// How, if at all, a walk's output should be made deterministic. See
+/// `WalkBuilder::sort_by_file_name` and `WalkBuilder::sort_by_path`.
+#[derive(Clone)]
+enum Sorter {
+    ByName(FileNameCmp),
+    ByPath(PathCmp),
+}
+
+impl Sorter {
+    /// Compare two sibling directory entries by file name, dispatching to
+    /// whichever comparator this sorter was built with. For `ByPath`, the
+    /// two file names are passed as bare, single-component paths, not as
+    /// full paths.
+    fn cmp_file_names(&self, a: &OsStr, b: &OsStr) -> cmp::Ordering {
+        match *self {
+            Sorter::ByName(ref cmp) => cmp(a, b),
+            Sorter::ByPath(ref cmp) => cmp(Path::new(a), Path::new(b)),
+        }
+    }
 }
 
 impl WalkBuilder {
@@ -345,8 +760,16 @@ impl WalkBuilder {
             ig_builder: IgnoreBuilder::new(),
             parents: true,
             max_depth: None,
+            max_filesize: None,
             follow_links: false,
+            same_file_system: false,
             threads: 0,
+            sorter: None,
+            ordered: false,
+            git_modified_rev: None,
+            filter: None,
+            read_dir_hook: None,
+            fs: Arc::new(OsFileSystem),
         }
     }
 
@@ -354,6 +777,7 @@ impl WalkBuilder {
     pub fn build(&self) -> Walk {
         let follow_links = self.follow_links;
         let max_depth = self.max_depth;
+        let sorter = self.sorter.clone();
         let its = self.paths.iter().map(move |p| {
             if p == Path::new("-") {
                 (p.to_path_buf(), None)
@@ -363,6 +787,10 @@ impl WalkBuilder {
                 if let Some(max_depth) = max_depth {
                     wd = wd.max_depth(max_depth);
                 }
+                if let Some(ref sorter) = sorter {
+                    let sorter = sorter.clone();
+                    wd = wd.sort_by(move |a, b| sorter.cmp_file_names(a, b));
+                }
                 (p.to_path_buf(), Some(WalkEventIter::from(wd)))
             }
         }).collect::<Vec<_>>().into_iter();
@@ -373,6 +801,11 @@ impl WalkBuilder {
             ig_root: ig_root.clone(),
             ig: ig_root.clone(),
             parents: self.parents,
+            max_filesize: self.max_filesize,
+            same_file_system: self.same_file_system,
+            root_device: None,
+            git_modified: self.build_git_modified().map(Arc::new),
+            filter: self.filter.clone(),
         }
     }
 
@@ -386,12 +819,74 @@ impl WalkBuilder {
             paths: self.paths.clone().into_iter(),
             ig_root: self.ig_builder.build(),
             max_depth: self.max_depth,
+            max_filesize: self.max_filesize,
             follow_links: self.follow_links,
+            same_file_system: self.same_file_system,
             parents: self.parents,
             threads: self.threads,
+            sorter: self.parallel_sorter(),
+            git_modified: self.build_git_modified().map(Arc::new),
+            filter: self.filter.clone(),
+            read_dir_hook: self.read_dir_hook.clone(),
+            fs: self.fs.clone(),
         }
     }
 
+    /// The sorter `build_parallel` should hand to `WalkParallel`.
+    ///
+    /// This is `self.sorter` unchanged, except that when no sorter has
+    /// been set but `ordered` is enabled, a plain byte-wise file name
+    /// comparator is synthesized so the index-path machinery that backs
+    /// `sort_by_file_name` kicks in anyway.
+    fn parallel_sorter(&self) -> Option<Sorter> {
+        self.sorter.clone().or_else(|| {
+            if self.ordered {
+                Some(Sorter::ByName(Arc::new(|a: &OsStr, b: &OsStr| a.cmp(b))))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Build a `futures::Stream` over this walk's results.
+    ///
+    /// Directory reads still happen on `build_parallel`'s worker threads;
+    /// this just bridges that callback-based producer to an async
+    /// consumer (tokio, async-std, an LSP server's event loop, ...) over a
+    /// bounded channel, with backpressure: once 256 entries are queued and
+    /// unconsumed, the producer threads block until the stream is polled
+    /// again. Use `build_stream_with_buffer` to change that bound.
+    ///
+    /// Requires the `stream` feature.
+    #[cfg(feature = "stream")]
+    pub fn build_stream(&self) -> WalkStream {
+        self.build_stream_with_buffer(256)
+    }
+
+    /// Like `build_stream`, but with an explicit channel buffer size.
+    ///
+    /// Requires the `stream` feature.
+    #[cfg(feature = "stream")]
+    pub fn build_stream_with_buffer(&self, buffer: usize) -> WalkStream {
+        let (tx, rx) = mpsc::channel(buffer);
+        let walker = self.build_parallel();
+        thread::spawn(move || {
+            walker.run(move |result| {
+                // `wait()` turns the async `Sink::send` into a blocking
+                // call, which is exactly the backpressure we want: this
+                // blocks the producer thread until the stream is polled
+                // and makes room.
+                match tx.clone().send(result).wait() {
+                    Ok(_) => WalkState::Continue,
+                    // The receiving end of the stream was dropped, so
+                    // there's no point in continuing to walk.
+                    Err(_) => WalkState::Quit,
+                }
+            });
+        });
+        WalkStream { rx: rx }
+    }
+
     /// Add a file path to the iterator.
     ///
     /// Each additional file path added is traversed recursively. This should
@@ -410,12 +905,48 @@ impl WalkBuilder {
         self
     }
 
+    /// Whether to ignore files above the specified limit.
+    ///
+    /// The default, `None`, imposes no limit. When set, any regular file
+    /// whose length exceeds `filesize` bytes is skipped rather than
+    /// yielded. This requires a `metadata` call per file, so it's only
+    /// paid when a limit is actually configured.
+    pub fn max_filesize(&mut self, filesize: Option<u64>) -> &mut WalkBuilder {
+        self.max_filesize = filesize;
+        self
+    }
+
     /// Whether to follow symbolic links or not.
     pub fn follow_links(&mut self, yes: bool) -> &mut WalkBuilder {
         self.follow_links = yes;
         self
     }
 
+    /// Whether to stay on the same file system as each root.
+    ///
+    /// When enabled, a directory is only descended into if it lives on the
+    /// same device (`st_dev` on Unix) as the root path it was found under.
+    /// This is useful for skipping network mounts, `/proc` and other
+    /// special file systems, and bind mounts, without having to list them
+    /// all as overrides.
+    ///
+    /// Each root path given to this builder is checked against its own
+    /// device, so walking several roots on different file systems still
+    /// descends into all of them; only a root's own subtree is pruned at
+    /// its mount boundaries.
+    ///
+    /// Under `build_parallel`, a directory on a different device is still
+    /// yielded to the caller (so it shows up in the output, same as any
+    /// other entry); it just never has its own children read. `build`'s
+    /// single-threaded walk skips such a directory outright instead, since
+    /// `walkdir` has no equivalent "yield without descending" option.
+    ///
+    /// Disabled by default.
+    pub fn same_file_system(&mut self, yes: bool) -> &mut WalkBuilder {
+        self.same_file_system = yes;
+        self
+    }
+
     /// The number of threads to use for traversal.
     ///
     /// Note that this only has an effect when using `build_parallel`.
@@ -531,6 +1062,444 @@ impl WalkBuilder {
         self.ig_builder.git_exclude(yes);
         self
     }
+
+    /// Add a custom ignore file name.
+    ///
+    /// Each custom ignore file is read in a directory just like `.ignore`
+    /// (same gitignore syntax, same per-directory nesting), and takes
+    /// precedence just above `.ignore`. Multiple custom ignore file names
+    /// can be added, and are applied in the order in which they were
+    /// added.
+    ///
+    /// This is useful for tools that want to support their own
+    /// project-specific ignore file in addition to `.gitignore` and
+    /// `.ignore`, e.g., `.rgignore`.
+    pub fn add_custom_ignore_filename<S: AsRef<OsStr>>(
+        &mut self,
+        file_name: S,
+    ) -> &mut WalkBuilder {
+        self.ig_builder.add_custom_ignore_filename(file_name);
+        self
+    }
+
+    /// Sort directory entries by file name using the given comparator.
+    ///
+    /// `build` will pass this straight through to `walkdir`, which yields
+    /// a simple, fully deterministic pre-order traversal.
+    ///
+    /// `build_parallel` keeps reading directories concurrently across
+    /// worker threads, but tags each directory entry with its sorted
+    /// position relative to its siblings (an "index path" from the root)
+    /// and reorders completed entries through a small buffer before they
+    /// reach the caller, so the emitted sequence is identical to what a
+    /// single-threaded, sorted walk would produce. Memory use stays
+    /// bounded to roughly the breadth of the subtrees currently in
+    /// flight, since an entry is only released once every entry with a
+    /// smaller index path has already been emitted.
+    ///
+    /// This is useful any time reproducible output matters more than
+    /// emitting results the instant they're ready, e.g., tests, diffing
+    /// two runs or caching results.
+    ///
+    /// Calling this overrides any previous call to `sort_by_file_name` or
+    /// `sort_by_path`.
+    pub fn sort_by_file_name<F>(&mut self, cmp: F) -> &mut WalkBuilder
+        where F: Fn(&OsStr, &OsStr) -> cmp::Ordering + Send + Sync + 'static
+    {
+        self.sorter = Some(Sorter::ByName(Arc::new(cmp)));
+        self
+    }
+
+    /// Sort directory entries by full path using the given comparator.
+    ///
+    /// This behaves identically to `sort_by_file_name`, including its
+    /// guarantees under `build_parallel`, except that `cmp` is given each
+    /// of the two entries' file names as a single-component `Path`, so
+    /// `Path` methods such as `extension` can be used on them. `cmp` is
+    /// not given the entries' full paths: only siblings are compared, and
+    /// their parent directories are not part of what `cmp` sees.
+    ///
+    /// Calling this overrides any previous call to `sort_by_file_name` or
+    /// `sort_by_path`.
+    pub fn sort_by_path<F>(&mut self, cmp: F) -> &mut WalkBuilder
+        where F: Fn(&Path, &Path) -> cmp::Ordering + Send + Sync + 'static
+    {
+        self.sorter = Some(Sorter::ByPath(Arc::new(cmp)));
+        self
+    }
+
+    /// Make `build_parallel`'s output deterministic without requiring a
+    /// custom sort comparator.
+    ///
+    /// `sort_by_file_name` and `sort_by_path` already make `build_parallel`
+    /// deterministic as a side effect of choosing an order; this is for
+    /// callers who don't care which order siblings come out in, only that
+    /// it's the same order every run. Enabling it enlists the same
+    /// index-path buffering described on `sort_by_file_name`, using a
+    /// plain byte-wise comparison of file names to break ties.
+    ///
+    /// Has no effect if `sort_by_file_name` or `sort_by_path` has also
+    /// been called; those take precedence. Has no effect on `build`, whose
+    /// single-threaded traversal never reorders output to begin with.
+    ///
+    /// Disabled by default.
+    pub fn ordered(&mut self, yes: bool) -> &mut WalkBuilder {
+        self.ordered = yes;
+        self
+    }
+
+    /// Restrict the walk to files added or modified relative to `rev`.
+    ///
+    /// When set, only paths that `git diff --name-only <rev>` reports
+    /// (and the directories leading to them) are yielded; everything else
+    /// is pruned before it's ever stat'd. If `rev` is `None`, the
+    /// merge-base between `HEAD` and its upstream tracking branch is used.
+    ///
+    /// The modified-path set is intersected with the normal gitignore-
+    /// respecting walk, and computed once per `build`/`build_parallel`
+    /// call by shelling out to `git`. If that fails (e.g., the root isn't
+    /// inside a git repository), the walk proceeds as if this option
+    /// hadn't been set.
+    pub fn git_modified(&mut self, rev: Option<&str>) -> &mut WalkBuilder {
+        self.git_modified_rev = Some(rev.map(|r| r.to_string()));
+        self
+    }
+
+    /// Filter entries with a custom predicate, mirroring walkdir's
+    /// `filter_entry`.
+    ///
+    /// The predicate is consulted after all ignore/override/file-type
+    /// matching has run, but before an entry is yielded. When it returns
+    /// `false` for a directory, that directory's entire subtree is
+    /// pruned, just as if it had been excluded by an ignore rule; when it
+    /// returns `false` for a file, only that file is skipped.
+    ///
+    /// This lets callers express arbitrary skip logic (mtime windows,
+    /// path-depth rules, ...) without reimplementing traversal.
+    pub fn filter_entry<P>(&mut self, predicate: P) -> &mut WalkBuilder
+        where P: Fn(&DirEntry) -> bool + Send + Sync + 'static
+    {
+        self.filter = Some(Arc::new(predicate));
+        self
+    }
+
+    /// Add a hook that runs once per directory during `build_parallel`,
+    /// after its full child listing has been read but before any of those
+    /// children are queued for work or yielded, modeled on jwalk's
+    /// `process_read_dir`.
+    ///
+    /// The closure receives the directory's depth, its path, the `Vec` of
+    /// its (already ignore/override/type-filtered) children, and a
+    /// `&mut T` of per-directory client state. It may reorder or truncate
+    /// the `Vec` to control traversal order or prune whole subtrees more
+    /// cheaply than `filter_entry` (which only sees one entry at a time),
+    /// call `DirEntry::skip_descend` on an entry to have it yielded
+    /// without its own children ever being read, and write into the
+    /// client state.
+    ///
+    /// Each child directory inherits a clone of its parent's client state
+    /// as computed by the hook, starting from `T::default()` at each root
+    /// path. This lets state accumulated further up the tree (an
+    /// effective config, a running count, ...) flow down without a shared,
+    /// synchronized structure.
+    ///
+    /// This hook only applies to `build_parallel`; `build`'s
+    /// single-threaded walk has no equivalent extension point.
+    pub fn process_read_dir<T, F>(&mut self, f: F) -> &mut WalkBuilder
+        where T: Default + Clone + Send + 'static,
+              F: Fn(usize, &Path, &mut Vec<DirEntry>, &mut T)
+                  + Send + Sync + 'static
+    {
+        self.read_dir_hook = Some(ReadDirHook {
+            call: Arc::new(move |depth, path, dents, state| {
+                let state = state.downcast_mut::<T>()
+                    .expect("consistent process_read_dir client state type");
+                f(depth, path, dents, state);
+            }),
+            default: Arc::new(|| Box::new(T::default())),
+            clone_state: Arc::new(|state| {
+                let state = state.downcast_ref::<T>()
+                    .expect("consistent process_read_dir client state type");
+                Box::new(state.clone())
+            }),
+        });
+        self
+    }
+
+    /// Read directories and stat files through `fs` instead of hitting
+    /// the real file system directly.
+    ///
+    /// Directory listings, and the stats the walker makes while building
+    /// entries from them, go through `fs`, so a `FileSystem` impl can
+    /// present a different view of a tree, e.g. an overlay that hides
+    /// entries, as long as it can still produce real
+    /// `std::fs::FileType`/`Metadata` values, since `std` has no public
+    /// way to synthesize those from scratch.
+    ///
+    /// Not every stat goes through `fs`: `DirEntry::metadata` (which
+    /// `max_filesize` and `run_with_stats` use for file sizes) and the
+    /// device ids that `run_with_stats` compares for its own
+    /// `same_file_system` option read the real file system.
+    ///
+    /// Only affects `build_parallel`. `build`'s single-threaded iterator
+    /// is implemented directly on top of the `walkdir` crate, which
+    /// always reads the real file system.
+    ///
+    /// Defaults to `OsFileSystem`, which preserves today's behavior.
+    pub fn filesystem(&mut self, fs: Arc<FileSystem>) -> &mut WalkBuilder {
+        self.fs = fs;
+        self
+    }
+
+    fn build_git_modified(&self) -> Option<GitModified> {
+        let rev = match self.git_modified_rev {
+            None => return None,
+            Some(ref rev) => rev.as_ref().map(|s| s.as_str()),
+        };
+        let root = self.paths.get(0).map(|p| p.as_path())
+            .unwrap_or_else(|| Path::new("."));
+        GitModified::compute(root, rev).ok()
+    }
+}
+
+/// A built-in comparator for `WalkBuilder::sort_by_file_name` that orders
+/// file names alphanumerically: runs of ASCII digits are compared by their
+/// numeric value rather than byte-for-byte, so e.g. `file2` sorts before
+/// `file10`.
+pub fn sort_by_file_name_alphanumeric(
+    a: &OsStr,
+    b: &OsStr,
+) -> cmp::Ordering {
+    // This is lossy for non-UTF-8 names, but falls back to a plain byte
+    // comparison in that case, which is no worse than today's behavior.
+    let (a, b) = match (a.to_str(), b.to_str()) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return a.cmp(b),
+    };
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+    loop {
+        let (ca, cb) = match (a.peek(), b.peek()) {
+            (Some(&ca), Some(&cb)) => (ca, cb),
+            (Some(_), None) => return cmp::Ordering::Greater,
+            (None, Some(_)) => return cmp::Ordering::Less,
+            (None, None) => return cmp::Ordering::Equal,
+        };
+        if ca.is_ascii_digit() && cb.is_ascii_digit() {
+            let mut na = String::new();
+            let mut nb = String::new();
+            while a.peek().map_or(false, |c| c.is_ascii_digit()) {
+                na.push(a.next().unwrap());
+            }
+            while b.peek().map_or(false, |c| c.is_ascii_digit()) {
+                nb.push(b.next().unwrap());
+            }
+            let ordering = na.trim_start_matches('0').len()
+                .cmp(&nb.trim_start_matches('0').len())
+                .then(na.cmp(&nb));
+            if ordering != cmp::Ordering::Equal {
+                return ordering;
+            }
+        } else {
+            a.next();
+            b.next();
+            if ca != cb {
+                return ca.cmp(&cb);
+            }
+        }
+    }
+}
+
+/// Aggregate size and file-count statistics for one directory's subtree.
+///
+/// See `WalkParallel::run_with_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DirStats {
+    /// The total apparent size, in bytes, of every regular file in this
+    /// directory and all of its descendants.
+    pub size: u64,
+    /// The total number of regular files in this directory and all of its
+    /// descendants.
+    pub count: u64,
+}
+
+impl DirStats {
+    fn add(&mut self, other: DirStats) {
+        self.size += other.size;
+        self.count += other.count;
+    }
+}
+
+/// One node in the arena of directories currently being aggregated.
+struct StatsNode {
+    parent: Option<usize>,
+    path: PathBuf,
+    total: DirStats,
+    // `None` until this directory's own listing has been read and its
+    // (directory) children are known.
+    pending: Option<usize>,
+    finished_children: usize,
+}
+
+/// Accumulates `DirStats` for every directory in a walk and invokes a
+/// callback once a directory's subtree (including all nested
+/// directories) has been fully walked.
+///
+/// Nodes are kept in a `Vec`-backed arena rather than, say, a `HashMap`
+/// keyed by path, since each `Work` item already carries the index of its
+/// parent's node and new nodes are only ever appended.
+struct Aggregator {
+    nodes: Vec<StatsNode>,
+    on_dir: Box<FnMut(&Path, DirStats) + Send>,
+    same_file_system: bool,
+    root_dev: Option<u64>,
+}
+
+impl Aggregator {
+    fn new(
+        same_file_system: bool,
+        on_dir: Box<FnMut(&Path, DirStats) + Send>,
+    ) -> Aggregator {
+        Aggregator {
+            nodes: vec![],
+            on_dir: on_dir,
+            same_file_system: same_file_system,
+            root_dev: None,
+        }
+    }
+
+    fn enter_dir(&mut self, path: PathBuf, parent: Option<usize>) -> usize {
+        if self.same_file_system && parent.is_none() && self.root_dev.is_none() {
+            self.root_dev = dev_id(&path);
+        }
+        let idx = self.nodes.len();
+        self.nodes.push(StatsNode {
+            parent: parent,
+            path: path,
+            total: DirStats::default(),
+            pending: None,
+            finished_children: 0,
+        });
+        idx
+    }
+
+    /// Whether `path` lives on the same device as the walk's root. Always
+    /// true unless `same_file_system` was requested.
+    fn same_device(&self, path: &Path) -> bool {
+        if !self.same_file_system {
+            return true;
+        }
+        devices_match(self.root_dev, dev_id(path))
+    }
+
+    fn add_file(&mut self, dir_idx: usize, size: u64) {
+        self.nodes[dir_idx].total.size += size;
+        self.nodes[dir_idx].total.count += 1;
+    }
+
+    /// Record how many directory children `dir_idx` has, and release it
+    /// (and any of its now-complete ancestors) if that's zero.
+    fn set_child_count(&mut self, dir_idx: usize, count: usize) {
+        self.nodes[dir_idx].pending = Some(count);
+        self.maybe_finish(dir_idx);
+    }
+
+    fn maybe_finish(&mut self, idx: usize) {
+        let ready = match self.nodes[idx].pending {
+            Some(n) => self.nodes[idx].finished_children == n,
+            None => false,
+        };
+        if !ready {
+            return;
+        }
+        let path = self.nodes[idx].path.clone();
+        let total = self.nodes[idx].total;
+        (self.on_dir)(&path, total);
+        if let Some(parent) = self.nodes[idx].parent {
+            self.nodes[parent].total.add(total);
+            self.nodes[parent].finished_children += 1;
+            self.maybe_finish(parent);
+        }
+    }
+}
+
+fn dev_id(path: &Path) -> Option<u64> {
+    fs::metadata(path).ok().and_then(|md| device_id(&md))
+}
+
+#[cfg(unix)]
+fn device_id(md: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(md.dev())
+}
+
+// `std::os::windows::fs::MetadataExt::volume_serial_number` and
+// `file_index` would give us these on Windows too, but both are gated
+// behind the unstable `windows_by_handle` feature and so aren't available
+// on stable Rust. Getting real identity there needs either nightly or an
+// extra dependency (e.g. `winapi`/`same-file`) that isn't pulled in here,
+// so Windows falls back to the same "unknown" behavior as other
+// platforms: same-filesystem pruning and symlink-loop detection by id
+// both degrade to a no-op rather than ever reporting a false match.
+#[cfg(not(unix))]
+fn device_id(_: &Metadata) -> Option<u64> {
+    None
+}
+
+#[cfg(unix)]
+fn inode_id(md: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(md.ino())
+}
+
+// See the comment on `device_id`'s Windows fallback above: a real
+// `file_index` is gated behind the unstable `windows_by_handle` feature,
+// so it's not usable here on stable Rust.
+#[cfg(not(unix))]
+fn inode_id(_: &Metadata) -> Option<u64> {
+    None
+}
+
+/// Whether two device ids (as returned by `dev_id`/`device_id`) refer to
+/// the same file system. A missing id (e.g., on a platform where device
+/// ids aren't available) is treated as matching, so `same_file_system`
+/// degrades to a no-op rather than pruning everything.
+fn devices_match(a: Option<u64>, b: Option<u64>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a == b,
+        _ => true,
+    }
+}
+
+/// WalkStream adapts a `WalkParallel` to a `futures::Stream`.
+///
+/// This lets `ignore` drop into async applications without the caller
+/// reinventing a channel bridge. See `WalkBuilder::build_stream`.
+///
+/// Requires the `stream` feature.
+#[cfg(feature = "stream")]
+pub struct WalkStream {
+    rx: mpsc::Receiver<Result<DirEntry, Error>>,
+}
+
+#[cfg(feature = "stream")]
+impl Stream for WalkStream {
+    type Item = DirEntry;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<DirEntry>, Error> {
+        match self.rx.poll() {
+            Ok(Async::Ready(Some(Ok(dent)))) => {
+                Ok(Async::Ready(Some(dent)))
+            }
+            Ok(Async::Ready(Some(Err(err)))) => Err(err),
+            Ok(Async::Ready(None)) => Ok(Async::Ready(None)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            // The channel's receiver half can't itself produce an error;
+            // treat it the same as the producer thread hanging up.
+            Err(()) => Ok(Async::Ready(None)),
+        }
+    }
 }
 
 /// Walk is a recursive directory iterator over file paths in one or more
@@ -545,6 +1514,11 @@ pub struct Walk {
     ig_root: Ignore,
     ig: Ignore,
     parents: bool,
+    max_filesize: Option<u64>,
+    same_file_system: bool,
+    root_device: Option<u64>,
+    git_modified: Option<Arc<GitModified>>,
+    filter: Option<EntryFilter>,
 }
 
 impl Walk {
@@ -561,7 +1535,32 @@ impl Walk {
         if ent.depth() == 0 {
             return false;
         }
-        skip_path(&self.ig, ent.path(), ent.file_type().is_dir())
+        let is_dir = ent.file_type().is_dir();
+        if skip_path(&self.ig, ent.path(), is_dir) {
+            return true;
+        }
+        if is_dir && self.same_file_system
+            && !devices_match(self.root_device, dev_id(ent.path()))
+        {
+            return true;
+        }
+        if let Some(ref modified) = self.git_modified {
+            if is_dir {
+                return !modified.should_descend(ent.path());
+            }
+            return !modified.should_yield(ent.path(), false);
+        }
+        false
+    }
+
+    /// Returns true if `dent` is a regular file whose length exceeds the
+    /// configured `max_filesize`.
+    fn exceeds_max_filesize(&self, dent: &DirEntry) -> bool {
+        let limit = match self.max_filesize {
+            None => return false,
+            Some(limit) => limit,
+        };
+        dent.metadata().map(|md| md.len() > limit).unwrap_or(false)
     }
 }
 
@@ -581,6 +1580,9 @@ impl Iterator for Walk {
                         }
                         Some((path, Some(it))) => {
                             self.it = Some(it);
+                            if self.same_file_system {
+                                self.root_device = dev_id(&path);
+                            }
                             if self.parents && path.is_dir() {
                                 let (ig, err) = self.ig_root.add_parents(path);
                                 self.ig = ig;
@@ -614,7 +1616,14 @@ impl Iterator for Walk {
                     }
                     let (igtmp, err) = self.ig.add_child(ent.path());
                     self.ig = igtmp;
-                    return Some(Ok(DirEntry::new_walkdir(ent, err)));
+                    let dent = DirEntry::new_walkdir(ent, err);
+                    if let Some(ref filter) = self.filter {
+                        if !filter(&dent) {
+                            self.it.as_mut().unwrap().it.skip_current_dir();
+                            continue;
+                        }
+                    }
+                    return Some(Ok(dent));
                 }
                 Ok(WalkEvent::File(ent)) => {
                     if self.skip_entry(&ent) {
@@ -625,7 +1634,16 @@ impl Iterator for Walk {
                     if !ent.file_type().is_file() {
                         continue;
                     }
-                    return Some(Ok(DirEntry::new_walkdir(ent, None)));
+                    let dent = DirEntry::new_walkdir(ent, None);
+                    if self.exceeds_max_filesize(&dent) {
+                        continue;
+                    }
+                    if let Some(ref filter) = self.filter {
+                        if !filter(&dent) {
+                            continue;
+                        }
+                    }
+                    return Some(Ok(dent));
                 }
             }
         }
@@ -695,63 +1713,427 @@ impl Iterator for WalkEventIter {
 /// and precedence is explained in the documentation for `WalkBuilder`.
 ///
 /// Unlike `Walk`, this uses multiple threads for traversing a directory.
-pub struct WalkParallel {
-    paths: vec::IntoIter<PathBuf>,
-    ig_root: Ignore,
-    parents: bool,
+///
+/// WalkState is returned by the visitor given to `WalkParallel::run`, and
+/// gives the visitor the same early-termination power over a parallel walk
+/// that dropping an `Iterator` gives over `Walk`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WalkState {
+    /// Continue the walk as normal.
+    Continue,
+    /// If returned for a directory entry, the directory's children are
+    /// not visited. It has no effect on a file entry.
+    ///
+    /// When `build_parallel` orders its output (`sort_by_file_name`,
+    /// `sort_by_path` or `ordered`), this only takes effect for a
+    /// directory delivered as soon as its own listing is read; a
+    /// directory held back behind earlier entries has its children
+    /// visited regardless.
+    Skip,
+    /// Quit the entire walk as soon as possible, across every worker
+    /// thread. Note that because threads race to notice this, a handful
+    /// of entries discovered just before the quit may still be visited.
+    Quit,
+}
+
+impl WalkState {
+    fn is_quit(&self) -> bool {
+        *self == WalkState::Quit
+    }
+}
+
+/// A per-thread visitor, as produced by the builder given to
+/// `WalkParallel::run_with`. Unlike the single closure `run` accepts, this
+/// doesn't need to be `Sync`: each worker thread gets its own, so it's
+/// free to mutate thread-local state without any locking.
+type Visitor = Box<FnMut(Result<DirEntry, Error>) -> WalkState + Send>;
+
+pub struct WalkParallel {
+    paths: vec::IntoIter<PathBuf>,
+    ig_root: Ignore,
+    parents: bool,
     max_depth: Option<usize>,
+    max_filesize: Option<u64>,
     follow_links: bool,
+    same_file_system: bool,
     threads: usize,
+    sorter: Option<Sorter>,
+    git_modified: Option<Arc<GitModified>>,
+    filter: Option<EntryFilter>,
+    read_dir_hook: Option<ReadDirHook>,
+    fs: Arc<FileSystem>,
+}
+
+/// WalkState is returned by the visitor given to `WalkParallel::run`, and
+/// gives the visitor the same early-termination power over a parallel walk
+/// that dropping an `Iterator` gives over `Walk`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WalkState {
+    /// Continue the walk as normal.
+    Continue,
+    /// If returned for a directory entry, the directory's children are
+    /// not visited. It has no effect on a file entry.
+    ///
+    /// When `build_parallel` orders its output (`sort_by_file_name`,
+    /// `sort_by_path` or `ordered`), this only takes effect for a
+    /// directory delivered as soon as its own listing is read; a
+    /// directory held back behind earlier entries has its children
+    /// visited regardless.
+    Skip,
+    /// Quit the entire walk as soon as possible, across every worker
+    /// thread. Note that because threads race to notice this, a handful
+    /// of entries discovered just before the quit may still be visited.
+    Quit,
+}
+
+impl WalkState {
+    fn is_quit(&self) -> bool {
+        *self == WalkState::Quit
+    }
+}
+
+/// A per-thread visitor, as produced by the builder given to
+/// `WalkParallel::run_with`. Unlike the single closure `run` accepts, this
+/// doesn't need to be `Sync`: each worker thread gets its own, so it's
+/// free to mutate thread-local state without any locking.
+type Visitor = Box<FnMut(Result<DirEntry, Error>) -> WalkState + Send>;
+
+/// An index path uniquely identifies a directory entry's position in a
+/// globally sorted, pre-order traversal of the tree rooted at a path given
+/// to `WalkBuilder`. The root paths are `[0]`, `[1]`, etc.; the first
+/// child of the third root path is `[2, 0]`.
+type IndexPath = Vec<usize>;
+
+/// Move an index path to the next sibling at the same depth.
+fn next_sibling(index_path: &mut IndexPath) {
+    if let Some(last) = index_path.last_mut() {
+        *last += 1;
+    }
+}
+
+/// A directory entry tagged with its index path, pending emission in
+/// sorted order.
+struct OrderedEntry {
+    index_path: IndexPath,
+    is_dir: bool,
+    result: Result<DirEntry, Error>,
+}
+
+impl PartialEq for OrderedEntry {
+    fn eq(&self, other: &OrderedEntry) -> bool {
+        self.index_path == other.index_path
+    }
+}
+impl Eq for OrderedEntry {}
+impl PartialOrd for OrderedEntry {
+    fn partial_cmp(&self, other: &OrderedEntry) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for OrderedEntry {
+    fn cmp(&self, other: &OrderedEntry) -> cmp::Ordering {
+        // Reversed so that `BinaryHeap`, a max-heap, pops the smallest
+        // index path first.
+        other.index_path.cmp(&self.index_path)
+    }
+}
+
+/// Buffers out-of-order completions from parallel workers and releases
+/// them to the caller in the order a single-threaded, sorted, pre-order
+/// walk would have produced.
+struct Orderer {
+    heap: BinaryHeap<OrderedEntry>,
+    next: IndexPath,
+    // The number of children a directory has, keyed by its own index
+    // path. Only known once that directory's listing has been read and
+    // sorted, which is why we can't simply advance past a directory the
+    // moment we've emitted it.
+    children: ::std::collections::HashMap<IndexPath, usize>,
+}
+
+impl Orderer {
+    fn new() -> Orderer {
+        Orderer {
+            heap: BinaryHeap::new(),
+            // The first root path is always index `[0]`.
+            next: vec![0],
+            children: ::std::collections::HashMap::new(),
+        }
+    }
+
+    fn push(&mut self, entry: OrderedEntry) {
+        self.heap.push(entry);
+    }
+
+    fn set_children(&mut self, index_path: IndexPath, count: usize) {
+        self.children.insert(index_path, count);
+    }
+
+    /// Pops and returns every entry that can now be released without
+    /// blocking.
+    fn drain_ready(&mut self) -> Vec<(IndexPath, Result<DirEntry, Error>)> {
+        let mut ready = vec![];
+        loop {
+            // `next` descends into a directory (pushing a `0`) the moment
+            // its child count is known, but nothing undoes that descent
+            // once the directory's last child has been released: the
+            // bare `next_sibling` below just bumps the last component,
+            // which can walk `next` past the end of its parent's known
+            // child count into an index path nothing will ever fill.
+            // Climb back out of any directory (or the top-level root
+            // list) whose children have all been accounted for, one level
+            // at a time, since the parent we land on may itself have just
+            // been exhausted by the same move.
+            while self.next.len() > 1 {
+                let parent_len = self.next.len() - 1;
+                let last = self.next[parent_len];
+                match self.children.get(&self.next[..parent_len]) {
+                    Some(&count) if last >= count => {
+                        self.next.truncate(parent_len);
+                        next_sibling(&mut self.next);
+                    }
+                    _ => break,
+                }
+            }
+            if let Some(&count) = self.children.get(&self.next) {
+                if count == 0 {
+                    next_sibling(&mut self.next);
+                } else {
+                    self.next.push(0);
+                }
+                continue;
+            }
+            match self.heap.peek() {
+                Some(entry) if entry.index_path == self.next => {}
+                _ => break,
+            }
+            let entry = self.heap.pop().unwrap();
+            let is_dir = entry.is_dir;
+            ready.push((entry.index_path.clone(), entry.result));
+            if is_dir {
+                // Wait until `set_children` tells us how many children
+                // this directory has before advancing past it.
+                break;
+            }
+            next_sibling(&mut self.next);
+        }
+        ready
+    }
+}
+
+/// Feed a root entry through `orderer` (if sorting is enabled) and call
+/// `visitor` on whatever it now allows through, exactly like `Worker::emit`
+/// does for entries discovered while walking. Root paths are visited from
+/// the main thread rather than a worker, so they need their own copy of
+/// this logic to participate in the same ordering.
+///
+/// Returns the `WalkState` the caller's own entry (identified by
+/// `index_path`) was given, or `WalkState::Quit` if `visitor` asked to
+/// quit for any entry released by this call. If sorting buffered the
+/// caller's entry behind an earlier root that hasn't finished yet, it
+/// isn't delivered during this call and this returns
+/// `WalkState::Continue`.
+fn emit_root(
+    orderer: &Option<Arc<Mutex<Orderer>>>,
+    visitor: &mut FnMut(Result<DirEntry, Error>) -> WalkState,
+    index_path: IndexPath,
+    is_dir: bool,
+    result: Result<DirEntry, Error>,
+) -> WalkState {
+    match *orderer {
+        None => visitor(result),
+        Some(ref orderer) => {
+            let ready = {
+                let mut orderer = orderer.lock().unwrap();
+                orderer.push(OrderedEntry {
+                    index_path: index_path.clone(),
+                    is_dir: is_dir,
+                    result: result,
+                });
+                orderer.drain_ready()
+            };
+            let mut state = WalkState::Continue;
+            for (ip, result) in ready {
+                let s = visitor(result);
+                if s.is_quit() || (ip == index_path && !state.is_quit()) {
+                    state = s;
+                }
+            }
+            state
+        }
+    }
 }
 
 impl WalkParallel {
     /// Execute the parallel recursive directory iterator. `f` is called for
     /// every file or directory.
+    ///
+    /// The `WalkState` returned by `f` controls how the walk proceeds:
+    /// `WalkState::Skip` prevents a directory's children from being
+    /// visited, and `WalkState::Quit` stops every worker thread as soon as
+    /// possible.
     pub fn run<F>(
         self,
         f: F,
-    ) where F: Fn(Result<DirEntry, Error>) + Send + Sync + 'static {
+    ) where F: Fn(Result<DirEntry, Error>) -> WalkState + Send + Sync + 'static {
         let f = Arc::new(f);
+        self.run_with(move || -> Visitor {
+            let f = f.clone();
+            Box::new(move |result| f(result))
+        })
+    }
+
+    /// Like `run`, but instead of a single closure shared (and thus
+    /// `Sync`) across every worker thread, `builder` is called once per
+    /// thread to produce that thread's own visitor. Since each worker owns
+    /// its visitor exclusively, a consumer can accumulate into thread-local
+    /// state (e.g. a `Vec` built up without a lock) and merge results
+    /// after the walk completes, rather than contending on a shared
+    /// `Mutex` for every entry.
+    pub fn run_with<B>(self, builder: B)
+    where B: FnMut() -> Visitor {
+        self.run_inner(builder, None)
+    }
+
+    /// Like `run`, but also aggregates per-directory size/file-count
+    /// statistics, invoking `on_dir` once a directory's entire subtree
+    /// (including nested directories) has finished being walked.
+    ///
+    /// Each worker accumulates into a shared arena of directories as it
+    /// reads them, rolling child totals up into their parent as soon as
+    /// they complete; `on_dir` fires bottom-up, so a directory is only
+    /// reported once every directory beneath it has already been
+    /// reported. If `same_file_system` is set, directories on a different
+    /// device than the walk's first root are treated as empty for the
+    /// purposes of aggregation (though they're still walked and yielded
+    /// to `f` as usual).
+    pub fn run_with_stats<F, A>(
+        self,
+        f: F,
+        same_file_system: bool,
+        on_dir: A,
+    ) where
+        F: Fn(Result<DirEntry, Error>) -> WalkState + Send + Sync + 'static,
+        A: FnMut(&Path, DirStats) + Send + 'static,
+    {
+        let aggregator = Aggregator::new(same_file_system, Box::new(on_dir));
+        let f = Arc::new(f);
+        self.run_inner(
+            move || -> Visitor {
+                let f = f.clone();
+                Box::new(move |result| f(result))
+            },
+            Some(Arc::new(Mutex::new(aggregator))),
+        )
+    }
+
+    fn run_inner<B>(
+        self,
+        mut builder: B,
+        stats: Option<Arc<Mutex<Aggregator>>>,
+    ) where B: FnMut() -> Visitor {
+        let sorter = self.sorter.clone();
+        let orderer = sorter.as_ref().map(|_| {
+            Arc::new(Mutex::new(Orderer::new()))
+        });
         let queue = Arc::new(MsQueue::new());
         let num_waiting = Arc::new(AtomicUsize::new(0));
         let num_quitting = Arc::new(AtomicUsize::new(0));
+        let quit = Arc::new(AtomicBool::new(false));
         let mut handles = vec![];
         for _ in 0..self.threads() {
             let worker = Worker {
-                f: f.clone(),
+                f: builder(),
                 ig_root: self.ig_root.clone(),
                 queue: queue.clone(),
                 is_waiting: false,
                 is_quitting: false,
                 num_waiting: num_waiting.clone(),
                 num_quitting: num_quitting.clone(),
+                quit: quit.clone(),
                 threads: self.threads(),
                 parents: self.parents,
                 max_depth: self.max_depth,
+                max_filesize: self.max_filesize,
                 follow_links: self.follow_links,
+                same_file_system: self.same_file_system,
+                sorter: sorter.clone(),
+                orderer: orderer.clone(),
+                git_modified: self.git_modified.clone(),
+                stats: stats.clone(),
+                filter: self.filter.clone(),
+                read_dir_hook: self.read_dir_hook.clone(),
+                fs: self.fs.clone(),
             };
             handles.push(thread::spawn(|| worker.run()));
         }
-        for path in self.paths {
+        // The root paths are visited directly from this thread, so they
+        // get a visitor of their own rather than stealing one meant for a
+        // worker.
+        //
+        // Every root, not just directories, must flow through the orderer
+        // the same way `Worker::emit` feeds it: the orderer only advances
+        // past index path `[i]` once something is pushed for it, so a
+        // stdin/file root that bypassed the orderer would leave it parked
+        // on `[i]` forever, silently withholding every root after it.
+        let mut root_visitor = builder();
+        for (i, path) in self.paths.enumerate() {
+            if quit.load(Ordering::SeqCst) {
+                break;
+            }
             if path == Path::new("-") {
-                f(Ok(DirEntry::new_stdin()));
+                let index_path = vec![i];
+                let result = Ok(DirEntry::new_stdin());
+                if emit_root(&orderer, &mut *root_visitor, index_path, false, result)
+                    .is_quit()
+                {
+                    quit.store(true, Ordering::SeqCst);
+                }
                 continue;
             }
-            let result = DirEntryRaw::from_path(0, path)
+            let result = DirEntryRaw::from_path(0, path, &*self.fs)
                 .map(|raw| DirEntry::new_raw(raw, None));
             let dent = match result {
                 Ok(dent) => dent,
                 Err(err) => {
-                    f(Err(err));
+                    let index_path = vec![i];
+                    if emit_root(
+                        &orderer, &mut *root_visitor, index_path, false, Err(err),
+                    ).is_quit() {
+                        quit.store(true, Ordering::SeqCst);
+                    }
                     continue;
                 }
             };
             if !dent.file_type().map_or(false, |t| t.is_dir()) {
-                f(Ok(dent));
+                let index_path = vec![i];
+                if emit_root(&orderer, &mut *root_visitor, index_path, false, Ok(dent))
+                    .is_quit()
+                {
+                    quit.store(true, Ordering::SeqCst);
+                }
             } else {
+                let root_device =
+                    if self.same_file_system { dent.device() } else { None };
+                let ancestors = if self.follow_links {
+                    let mut v = Vec::new();
+                    if let Some(id) = dent.dir_id() {
+                        v.push((dent.path().to_path_buf(), id));
+                    }
+                    Some(v)
+                } else {
+                    None
+                };
+                let read_dir_state = self.read_dir_hook.as_ref()
+                    .map(|hook| (hook.default)());
                 queue.push(Message::Work(Work {
                     dent: dent,
                     ignore: self.ig_root.clone(),
+                    index_path: vec![i],
+                    stats_parent: None,
+                    root_device: root_device,
+                    ancestors: ancestors,
+                    read_dir_state: read_dir_state,
                 }));
             }
         }
@@ -767,6 +2149,167 @@ impl WalkParallel {
             self.threads
         }
     }
+
+    /// Like `run_buffered`, but with the deadline and cap defaulted to
+    /// 100ms and 1000 entries, respectively.
+    pub fn run_buffered_default<F>(self, cmp: F) -> BufferedReceiver
+    where F: Fn(&DirEntry, &DirEntry) -> cmp::Ordering + Send + Sync + 'static
+    {
+        self.run_buffered(Duration::from_millis(100), 1000, cmp)
+    }
+
+    /// Run this walk in the background and return a `BufferedReceiver`
+    /// that yields its results.
+    ///
+    /// If the walk finishes within `max_buffer_time` and produces no more
+    /// than `max_buffer_length` entries, the receiver yields the entire
+    /// result sorted with `cmp` all at once. Otherwise, whatever has
+    /// accumulated so far is flushed (sorted once) and every entry after
+    /// that is handed to the caller the moment it's produced, unsorted.
+    ///
+    /// This is modeled on fd's receiver design: small, fast walks get
+    /// pretty, deterministic output, while huge walks never appear to
+    /// hang waiting for everything to finish.
+    ///
+    /// See `run_buffered_default` for the common case of just wanting
+    /// those defaults.
+    pub fn run_buffered<F>(
+        self,
+        max_buffer_time: Duration,
+        max_buffer_length: usize,
+        cmp: F,
+    ) -> BufferedReceiver
+    where F: Fn(&DirEntry, &DirEntry) -> cmp::Ordering + Send + Sync + 'static
+    {
+        let state = Arc::new(Mutex::new(BufferedState {
+            mode: BufferMode::Buffering,
+            buf: vec![],
+            streamed: VecDeque::new(),
+            done: false,
+        }));
+        let cond = Arc::new(Condvar::new());
+        let cmp: Arc<
+            Fn(&DirEntry, &DirEntry) -> cmp::Ordering + Send + Sync
+        > = Arc::new(cmp);
+
+        {
+            let state = state.clone();
+            let cond = cond.clone();
+            let cmp = cmp.clone();
+            thread::spawn(move || {
+                thread::sleep(max_buffer_time);
+                let mut state = state.lock().unwrap();
+                flush_to_streaming(&mut state, &cmp);
+                cond.notify_all();
+            });
+        }
+        {
+            let state = state.clone();
+            let cond = cond.clone();
+            let cmp = cmp.clone();
+            thread::spawn(move || {
+                self.run(move |result| {
+                    let mut state = state.lock().unwrap();
+                    match state.mode {
+                        BufferMode::Streaming => {
+                            state.streamed.push_back(result);
+                        }
+                        BufferMode::Buffering => {
+                            state.buf.push(result);
+                            if state.buf.len() > max_buffer_length {
+                                flush_to_streaming(&mut state, &cmp);
+                            }
+                        }
+                    }
+                    cond.notify_all();
+                    WalkState::Continue
+                });
+                let mut state = state.lock().unwrap();
+                if let BufferMode::Buffering = state.mode {
+                    sort_results(&mut state.buf, &cmp);
+                }
+                state.done = true;
+                cond.notify_all();
+            });
+        }
+        BufferedReceiver {
+            state: state,
+            cond: cond,
+            drained: vec![].into_iter(),
+        }
+    }
+}
+
+fn sort_results(
+    results: &mut Vec<Result<DirEntry, Error>>,
+    cmp: &Arc<Fn(&DirEntry, &DirEntry) -> cmp::Ordering + Send + Sync>,
+) {
+    results.sort_by(|a, b| match (a, b) {
+        (&Ok(ref a), &Ok(ref b)) => cmp(a, b),
+        _ => cmp::Ordering::Equal,
+    });
+}
+
+fn flush_to_streaming(
+    state: &mut BufferedState,
+    cmp: &Arc<Fn(&DirEntry, &DirEntry) -> cmp::Ordering + Send + Sync>,
+) {
+    if let BufferMode::Streaming = state.mode {
+        return;
+    }
+    let mut buf = mem::replace(&mut state.buf, vec![]);
+    sort_results(&mut buf, cmp);
+    state.streamed.extend(buf);
+    state.mode = BufferMode::Streaming;
+}
+
+enum BufferMode {
+    Buffering,
+    Streaming,
+}
+
+struct BufferedState {
+    mode: BufferMode,
+    buf: Vec<Result<DirEntry, Error>>,
+    streamed: VecDeque<Result<DirEntry, Error>>,
+    done: bool,
+}
+
+/// A `WalkParallel` consumer that buffers and sorts results for walks that
+/// finish quickly, and transparently falls back to live, unsorted
+/// streaming for walks that run long or produce a lot of output.
+///
+/// Created by `WalkParallel::run_buffered`.
+pub struct BufferedReceiver {
+    state: Arc<Mutex<BufferedState>>,
+    cond: Arc<Condvar>,
+    drained: vec::IntoIter<Result<DirEntry, Error>>,
+}
+
+impl Iterator for BufferedReceiver {
+    type Item = Result<DirEntry, Error>;
+
+    fn next(&mut self) -> Option<Result<DirEntry, Error>> {
+        if let Some(item) = self.drained.next() {
+            return Some(item);
+        }
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let BufferMode::Streaming = state.mode {
+                if let Some(item) = state.streamed.pop_front() {
+                    return Some(item);
+                }
+                if state.done {
+                    return None;
+                }
+            } else if state.done {
+                let buf = mem::replace(&mut state.buf, vec![]);
+                self.drained = buf.into_iter();
+                return self.drained.next();
+            }
+            state = self.cond.wait(state).unwrap();
+        }
+    }
 }
 
 enum Message {
@@ -777,61 +2320,206 @@ enum Message {
 struct Work {
     dent: DirEntry,
     ignore: Ignore,
+    index_path: IndexPath,
+    stats_parent: Option<usize>,
+    /// The device id of the root this work item descends from, when
+    /// `same_file_system` is enabled. Carried down unchanged to every
+    /// descendant so each one is compared against its *root's* device,
+    /// not its immediate parent's.
+    root_device: Option<u64>,
+    /// The chain of directories (with their `(device, inode)` ids) from
+    /// the root of this subtree down to and including `dent`, when
+    /// `follow_links` is enabled. Each branch of the walk carries its own
+    /// independent chain, since `DirEntryRaw` reads directories itself
+    /// rather than relying on walkdir's built-in loop detection.
+    ancestors: Option<Vec<(PathBuf, (u64, u64))>>,
+    /// This directory's inherited `process_read_dir` client state, when a
+    /// `WalkBuilder::process_read_dir` hook is set. Consumed and replaced
+    /// with the hook's updated state before being cloned into each child
+    /// directory's own `Work`.
+    read_dir_state: Option<ReadDirState>,
 }
 
 struct Worker {
-    f: Arc<Fn(Result<DirEntry, Error>) + Send + Sync + 'static>,
+    f: Visitor,
     ig_root: Ignore,
     queue: Arc<MsQueue<Message>>,
     is_waiting: bool,
     is_quitting: bool,
     num_waiting: Arc<AtomicUsize>,
     num_quitting: Arc<AtomicUsize>,
+    quit: Arc<AtomicBool>,
     threads: usize,
     parents: bool,
     max_depth: Option<usize>,
+    max_filesize: Option<u64>,
     follow_links: bool,
+    same_file_system: bool,
+    sorter: Option<Sorter>,
+    orderer: Option<Arc<Mutex<Orderer>>>,
+    git_modified: Option<Arc<GitModified>>,
+    stats: Option<Arc<Mutex<Aggregator>>>,
+    filter: Option<EntryFilter>,
+    read_dir_hook: Option<ReadDirHook>,
+    fs: Arc<FileSystem>,
 }
 
 impl Worker {
+    /// Emit a completed entry, either immediately or (when sorting is
+    /// enabled) by running it through the `Orderer` and releasing
+    /// whatever it now allows through in sorted order.
+    ///
+    /// Returns the `WalkState` the caller's own entry (identified by
+    /// `index_path`) was given, or `WalkState::Quit` if `f` asked to quit
+    /// for any entry released by this call. When sorting holds the
+    /// caller's entry back behind an earlier one, it isn't delivered
+    /// during this call and this returns `WalkState::Continue`; a `Skip`
+    /// that `f` gives it when it's released later has no effect.
+    fn emit(
+        &mut self,
+        index_path: IndexPath,
+        is_dir: bool,
+        result: Result<DirEntry, Error>,
+    ) -> WalkState {
+        match self.orderer {
+            None => (self.f)(result),
+            Some(ref orderer) => {
+                let ready = {
+                    let mut orderer = orderer.lock().unwrap();
+                    orderer.push(OrderedEntry {
+                        index_path: index_path.clone(),
+                        is_dir: is_dir,
+                        result: result,
+                    });
+                    orderer.drain_ready()
+                };
+                let mut state = WalkState::Continue;
+                for (ip, result) in ready {
+                    let s = (self.f)(result);
+                    if s.is_quit() || (ip == index_path && !state.is_quit()) {
+                        state = s;
+                    }
+                }
+                state
+            }
+        }
+    }
+
     fn run(mut self) {
         while let Some(mut work) = self.get_work() {
+            if self.quit.load(Ordering::SeqCst) {
+                return;
+            }
             let depth = work.dent.depth();
             if self.parents && depth == 0 {
                 let (ig, err) = self.ig_root.add_parents(work.dent.path());
                 work.ignore = ig;
                 if let Some(err) = err {
-                    (self.f)(Err(err));
+                    if self.emit_direct(Err(err)) {
+                        return;
+                    }
                 }
             }
-            let readdir = match fs::read_dir(work.dent.path()) {
+            // Read the directory before emitting its own entry so the
+            // failure path below has the `io::Error` to hand, but don't
+            // act on it yet: the entry still needs to go through the
+            // orderer/aggregator exactly like the success path does,
+            // since their bookkeeping already reserved this directory's
+            // slot the moment its parent accepted it as a child.
+            let readdir_result = self.fs.read_dir(work.dent.path());
+            let (ig, err) = work.ignore.add_child(work.dent.path());
+            work.ignore = ig;
+            work.dent.err = err;
+            let is_dir = work.dent.file_type().map_or(false, |t| t.is_dir());
+            let index_path = work.index_path.clone();
+            let dir_path = work.dent.path().to_path_buf();
+            let dir_stats_idx = self.stats.as_ref().map(|stats| {
+                let path = work.dent.path().to_path_buf();
+                stats.lock().unwrap().enter_dir(path, work.stats_parent)
+            });
+            let state = self.emit(index_path.clone(), is_dir, Ok(work.dent));
+            if state.is_quit() {
+                self.quit_all();
+                return;
+            }
+            if is_dir && state == WalkState::Skip {
+                // Don't descend into this directory's children, but do
+                // still let the orderer and the stats aggregator know it
+                // has none, so neither is stuck waiting on a subtree that
+                // will never arrive.
+                if self.abandon_dir(index_path, dir_stats_idx) {
+                    return;
+                }
+                continue;
+            }
+
+            let readdir = match readdir_result {
                 Ok(readdir) => readdir,
                 Err(err) => {
                     let err = Error::from(err)
-                        .with_path(work.dent.path()).with_depth(depth);
-                    (self.f)(Err(err));
+                        .with_path(&dir_path).with_depth(depth);
+                    if self.emit_direct(Err(err)) {
+                        return;
+                    }
+                    // This directory's own entry was already emitted
+                    // above; tell the orderer and the stats aggregator it
+                    // has no children (its listing couldn't be read) so
+                    // neither parks waiting on a subtree that will never
+                    // show up. Without this, every entry ordered after
+                    // this directory is silently dropped when `run`
+                    // returns, and `run_with_stats`'s `on_dir` never
+                    // fires for any of this directory's ancestors.
+                    if self.abandon_dir(index_path, dir_stats_idx) {
+                        return;
+                    }
                     continue;
                 }
             };
-            let (ig, err) = work.ignore.add_child(work.dent.path());
-            work.ignore = ig;
-            work.dent.err = err;
-            (self.f)(Ok(work.dent));
+
+            let mut fs_dents: Vec<FileSystemEntry> = vec![];
             for result in readdir {
-                let fs_dent = match result {
-                    Ok(fs_dent) => fs_dent,
+                match result {
+                    Ok(fs_dent) => fs_dents.push(fs_dent),
                     Err(err) => {
                         let err = Error::from(err).with_depth(depth + 1);
-                        (self.f)(Err(err));
-                        continue;
+                        if self.emit_direct(Err(err)) {
+                            return;
+                        }
                     }
-                };
-                let result = DirEntryRaw::from_entry(depth + 1, &fs_dent)
-                    .map(|raw| DirEntry::new_raw(raw, None));
-                let dent = match result {
+                }
+            }
+            if let Some(ref sorter) = self.sorter {
+                fs_dents.sort_by(|a, b| {
+                    sorter.cmp_file_names(a.file_name(), b.file_name())
+                });
+            }
+
+            // Build the DirEntry for every child up front and run it
+            // through the ignore/git-modified filters, so that the
+            // *accepted* count (not the raw readdir count) is what
+            // downstream bookkeeping (ordering, aggregation) waits for.
+            // Otherwise a filtered-out entry would leave a permanent gap
+            // in the index path / child count that the consumer could
+            // never advance past.
+            let mut accepted = vec![];
+            for fs_dent in fs_dents {
+                let is_symlink = fs_dent.file_type().is_symlink();
+                let result = if is_symlink && self.follow_links {
+                    DirEntryRaw::from_link(
+                        depth + 1, fs_dent.path().to_path_buf(), &*self.fs,
+                    )
+                } else {
+                    DirEntryRaw::from_entry(
+                        depth + 1, &fs_dent, &*self.fs, self.same_file_system,
+                        self.follow_links,
+                    )
+                }.map(|raw| DirEntry::new_raw(raw, None));
+                let mut dent = match result {
                     Ok(dent) => dent,
                     Err(err) => {
-                        (self.f)(Err(err));
+                        if self.emit_direct(Err(err)) {
+                            return;
+                        }
                         continue;
                     }
                 };
@@ -840,22 +2528,244 @@ impl Worker {
                 if skip_path(&work.ignore, dent.path(), is_dir) {
                     continue;
                 }
+                if is_dir && self.same_file_system
+                    && !devices_match(work.root_device, dent.device())
+                {
+                    // Still yield the directory itself; just don't
+                    // descend past the mount boundary it sits on.
+                    dent.skip_descend();
+                }
+                if is_dir && self.follow_links {
+                    let looped = dent.dir_id().and_then(|id| {
+                        work.ancestors.as_ref().and_then(|ancestors| {
+                            ancestors.iter()
+                                .find(|&&(_, aid)| aid == id)
+                                .map(|&(ref path, _)| path.clone())
+                        })
+                    });
+                    if let Some(ancestor) = looped {
+                        let err = Error::Loop {
+                            ancestor: ancestor,
+                            child: dent.path().to_path_buf(),
+                        };
+                        if self.emit_direct(Err(err)) {
+                            return;
+                        }
+                        continue;
+                    }
+                }
+                if let Some(ref modified) = self.git_modified {
+                    if is_dir {
+                        if !modified.should_descend(dent.path()) {
+                            continue;
+                        }
+                    } else if !modified.should_yield(dent.path(), false) {
+                        continue;
+                    }
+                }
+                if !is_dir && !is_file {
+                    continue;
+                }
+                if is_file {
+                    if let Some(limit) = self.max_filesize {
+                        let len = dent.metadata()
+                            .map(|md| md.len()).unwrap_or(0);
+                        if len > limit {
+                            continue;
+                        }
+                    }
+                }
+                if let Some(ref filter) = self.filter {
+                    if !filter(&dent) {
+                        continue;
+                    }
+                }
+                accepted.push((is_dir, dent));
+            }
+
+            if let Some(ref hook) = self.read_dir_hook {
+                let mut dents: Vec<DirEntry> =
+                    accepted.into_iter().map(|(_, dent)| dent).collect();
+                let mut state = work.read_dir_state.take()
+                    .unwrap_or_else(|| (hook.default)());
+                (hook.call)(depth, &dir_path, &mut dents, &mut state);
+                work.read_dir_state = Some(state);
+                accepted = dents.into_iter().map(|dent| {
+                    let is_dir = dent.file_type().map_or(false, |t| t.is_dir());
+                    (is_dir, dent)
+                }).collect();
+            }
+
+            if let Some(ref orderer) = self.orderer {
+                let ready = {
+                    let mut orderer = orderer.lock().unwrap();
+                    orderer.set_children(index_path.clone(), accepted.len());
+                    orderer.drain_ready()
+                };
+                for (_, result) in ready {
+                    if self.emit_direct(result) {
+                        return;
+                    }
+                }
+            }
+
+            // A cross-device directory gets its own independent
+            // aggregation root (reported to `on_dir` on its own) rather
+            // than being counted as one of `dir_stats_idx`'s pending
+            // children, since it will never report back to it.
+            let child_stats_parent: Vec<Option<usize>> = accepted.iter()
+                .map(|&(is_dir, ref dent)| {
+                    if !is_dir || !dent.should_descend() {
+                        return None;
+                    }
+                    let same_device = self.stats.as_ref().map_or(true, |s| {
+                        s.lock().unwrap().same_device(dent.path())
+                    });
+                    if same_device { dir_stats_idx } else { None }
+                })
+                .collect();
+            let num_dirs = accepted.iter().zip(&child_stats_parent)
+                .filter(|&(&(is_dir, _), parent)| {
+                    is_dir && *parent == dir_stats_idx
+                })
+                .count();
+            if let Some(idx) = dir_stats_idx {
+                let stats = self.stats.as_ref().unwrap();
+                stats.lock().unwrap().set_child_count(idx, num_dirs);
+            }
+
+            for (i, (is_dir, dent)) in accepted.into_iter().enumerate() {
+                let child_index_path = {
+                    let mut p = index_path.clone();
+                    p.push(i);
+                    p
+                };
                 if !is_dir {
-                    if is_file {
-                        (self.f)(Ok(dent));
+                    if let Some(idx) = dir_stats_idx {
+                        let size = dent.metadata().map(|md| md.len()).unwrap_or(0);
+                        self.stats.as_ref().unwrap()
+                            .lock().unwrap().add_file(idx, size);
+                    }
+                    if self.emit(child_index_path, false, Ok(dent)).is_quit() {
+                        self.quit_all();
+                        return;
+                    }
+                } else if !dent.should_descend() {
+                    // A `process_read_dir` hook or `same_file_system`
+                    // pruning marked this one to be yielded without ever
+                    // having its own children read,
+                    // so emit it like any other directory, but tell the
+                    // orderer up front that it has none.
+                    if self.emit(
+                        child_index_path.clone(), true, Ok(dent),
+                    ).is_quit() {
+                        self.quit_all();
+                        return;
+                    }
+                    if let Some(ref orderer) = self.orderer {
+                        let ready = {
+                            let mut orderer = orderer.lock().unwrap();
+                            orderer.set_children(child_index_path, 0);
+                            orderer.drain_ready()
+                        };
+                        for (_, result) in ready {
+                            if self.emit_direct(result) {
+                                return;
+                            }
+                        }
                     }
                 } else {
+                    let ancestors = work.ancestors.as_ref().map(|ancestors| {
+                        let mut ancestors = ancestors.clone();
+                        if let Some(id) = dent.dir_id() {
+                            ancestors.push((dent.path().to_path_buf(), id));
+                        }
+                        ancestors
+                    });
+                    let read_dir_state = work.read_dir_state.as_ref()
+                        .map(|state| {
+                            (self.read_dir_hook.as_ref().unwrap()
+                                .clone_state)(state)
+                        });
                     self.queue.push(Message::Work(Work {
                         dent: dent,
                         ignore: work.ignore.clone(),
+                        index_path: child_index_path,
+                        stats_parent: child_stats_parent[i],
+                        root_device: work.root_device,
+                        ancestors: ancestors,
+                        read_dir_state: read_dir_state,
                     }));
                 }
             }
         }
     }
 
+    /// Tell the orderer and the stats aggregator that the directory at
+    /// `index_path` (whose own entry has already been emitted) will
+    /// never have any children reported, because its listing couldn't be
+    /// read or because a visitor chose to skip it.
+    ///
+    /// `Orderer` and `Aggregator` only learn a directory's child count
+    /// once that directory's own `Work` item is processed; until then,
+    /// they're parked waiting on it. A directory that never reaches that
+    /// point (caller `continue`s out early) would otherwise leave the
+    /// orderer stuck on this exact index path forever, silently dropping
+    /// every entry ordered after it, and leave every ancestor in the
+    /// aggregator waiting on a `finished_children` count that can never
+    /// reach `pending` — so `on_dir` never fires for the root, even on an
+    /// otherwise-complete walk. Reporting zero children here lets both
+    /// advance immediately instead.
+    ///
+    /// Returns `true` if the caller should stop immediately (a visitor
+    /// asked to quit while draining now-ready ordered entries).
+    fn abandon_dir(
+        &mut self,
+        index_path: IndexPath,
+        dir_stats_idx: Option<usize>,
+    ) -> bool {
+        if let Some(ref orderer) = self.orderer {
+            let ready = {
+                let mut orderer = orderer.lock().unwrap();
+                orderer.set_children(index_path, 0);
+                orderer.drain_ready()
+            };
+            for (_, result) in ready {
+                if self.emit_direct(result) {
+                    return true;
+                }
+            }
+        }
+        if let Some(idx) = dir_stats_idx {
+            self.stats.as_ref().unwrap()
+                .lock().unwrap().set_child_count(idx, 0);
+        }
+        false
+    }
+
+    /// Run `f` directly on a result that's already been through the
+    /// orderer (or never needed to be, like a bare readdir error). Returns
+    /// `true` if `f` asked us to quit.
+    fn emit_direct(&mut self, result: Result<DirEntry, Error>) -> bool {
+        if (self.f)(result).is_quit() {
+            self.quit_all();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Tell every worker thread, including this one, to stop as soon as
+    /// possible.
+    fn quit_all(&self) {
+        self.quit.store(true, Ordering::SeqCst);
+    }
+
     fn get_work(&mut self) -> Option<Work> {
         loop {
+            if self.quit.load(Ordering::SeqCst) {
+                return None;
+            }
             match self.queue.try_pop() {
                 Some(Message::Work(work)) => {
                     self.waiting(false);
@@ -949,13 +2859,20 @@ fn skip_path(ig: &Ignore, path: &Path, is_dir: bool) -> bool {
 
 #[cfg(test)]
 mod tests {
+    use std::env;
     use std::fs::{self, File};
+    use std::io;
     use std::io::Write;
     use std::path::Path;
+    use std::process::Command;
+    use std::sync::{Arc, Mutex};
 
     use tempdir::TempDir;
 
-    use super::{Walk, WalkBuilder};
+    use super::{
+        DirStats, FileSystem, FileSystemEntry, OsFileSystem, Walk,
+        WalkBuilder, WalkParallel, WalkState,
+    };
 
     fn wfile<P: AsRef<Path>>(path: P, contents: &str) {
         let mut file = File::create(path).unwrap();
@@ -994,6 +2911,286 @@ mod tests {
         paths
     }
 
+    /// Like `walk_collect`, but for `build_parallel`'s callback-based API.
+    /// Since entries can arrive from any worker thread, they're collected
+    /// behind a `Mutex` and sorted before comparison, same as
+    /// `walk_collect` does for the single-threaded iterator.
+    fn walk_parallel_collect(
+        prefix: &Path,
+        walker: WalkParallel,
+    ) -> Vec<String> {
+        let paths = Arc::new(Mutex::new(vec![]));
+        {
+            let paths = paths.clone();
+            let prefix = prefix.to_path_buf();
+            walker.run(move |result| {
+                let dent = result.unwrap();
+                let path = dent.path().strip_prefix(&prefix).unwrap();
+                if !path.as_os_str().is_empty() {
+                    paths.lock().unwrap()
+                        .push(normal_path(path.to_str().unwrap()));
+                }
+                WalkState::Continue
+            });
+        }
+        let mut paths = Arc::try_unwrap(paths).unwrap().into_inner().unwrap();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn parallel_no_ignores() {
+        let td = TempDir::new("walk-test-").unwrap();
+        mkdirp(td.path().join("a/b/c"));
+        mkdirp(td.path().join("x/y"));
+        wfile(td.path().join("a/b/foo"), "");
+        wfile(td.path().join("x/y/foo"), "");
+
+        let walker = WalkBuilder::new(td.path()).build_parallel();
+        let got = walk_parallel_collect(td.path(), walker);
+        assert_eq!(got, mkpaths(&[
+            "x", "x/y", "x/y/foo", "a", "a/b", "a/b/foo", "a/b/c",
+        ]));
+    }
+
+    #[test]
+    fn parallel_ordered_is_sorted() {
+        let td = TempDir::new("walk-test-").unwrap();
+        mkdirp(td.path().join("a"));
+        mkdirp(td.path().join("b"));
+        wfile(td.path().join("a/2"), "");
+        wfile(td.path().join("a/1"), "");
+        wfile(td.path().join("b/1"), "");
+
+        let mut builder = WalkBuilder::new(td.path());
+        builder.sort_by_file_name(|a, b| a.cmp(b));
+        let walker = builder.build_parallel();
+
+        let order = Arc::new(Mutex::new(vec![]));
+        {
+            let order = order.clone();
+            let prefix = td.path().to_path_buf();
+            walker.run(move |result| {
+                let dent = result.unwrap();
+                let path = dent.path().strip_prefix(&prefix).unwrap();
+                if !path.as_os_str().is_empty() {
+                    order.lock().unwrap()
+                        .push(normal_path(path.to_str().unwrap()));
+                }
+                WalkState::Continue
+            });
+        }
+        let order = Arc::try_unwrap(order).unwrap().into_inner().unwrap();
+        // `sort_by_file_name` with `build_parallel` guarantees this exact
+        // pre-order; unlike `walk_parallel_collect`, this isn't re-sorted
+        // before comparison, since what's under test is the order itself.
+        assert_eq!(order, vec![
+            normal_path("a"), normal_path("a/1"), normal_path("a/2"),
+            normal_path("b"), normal_path("b/1"),
+        ]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn parallel_ordered_survives_unreadable_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let td = TempDir::new("walk-test-").unwrap();
+        mkdirp(td.path().join("a"));
+        mkdirp(td.path().join("b"));
+        mkdirp(td.path().join("c"));
+        wfile(td.path().join("a/1"), "");
+        wfile(td.path().join("c/1"), "");
+        fs::set_permissions(
+            td.path().join("b"), fs::Permissions::from_mode(0o000),
+        ).unwrap();
+
+        let mut builder = WalkBuilder::new(td.path());
+        builder.sort_by_file_name(|a, b| a.cmp(b));
+        let walker = builder.build_parallel();
+
+        let results = Arc::new(Mutex::new(vec![]));
+        {
+            let results = results.clone();
+            walker.run(move |result| {
+                results.lock().unwrap().push(result.is_ok());
+                WalkState::Continue
+            });
+        }
+        // Restore permissions so the `TempDir` can remove `b` on drop.
+        fs::set_permissions(
+            td.path().join("b"), fs::Permissions::from_mode(0o755),
+        ).unwrap();
+
+        let results = Arc::try_unwrap(results).unwrap().into_inner().unwrap();
+        // Before the orderer/aggregator were taught to advance past a
+        // directory whose listing can't be read, `c` (ordered after the
+        // unreadable `b`) and everything under it was silently dropped.
+        assert!(
+            results.iter().any(|&ok| !ok),
+            "expected an Err entry for the unreadable directory",
+        );
+        assert!(
+            results.len() >= 6,
+            "expected root, a, a/1, b, c and c/1, got {} entries",
+            results.len(),
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn stats_finish_despite_unreadable_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let td = TempDir::new("walk-test-").unwrap();
+        mkdirp(td.path().join("a"));
+        mkdirp(td.path().join("b"));
+        wfile(td.path().join("a/1"), "hello");
+        fs::set_permissions(
+            td.path().join("b"), fs::Permissions::from_mode(0o000),
+        ).unwrap();
+
+        let walker = WalkBuilder::new(td.path()).build_parallel();
+        let root_stats = Arc::new(Mutex::new(None));
+        {
+            let root_stats = root_stats.clone();
+            let root = td.path().to_path_buf();
+            walker.run_with_stats(
+                |_| WalkState::Continue,
+                false,
+                move |path, stats| {
+                    if path == root.as_path() {
+                        *root_stats.lock().unwrap() = Some(stats);
+                    }
+                },
+            );
+        }
+        fs::set_permissions(
+            td.path().join("b"), fs::Permissions::from_mode(0o755),
+        ).unwrap();
+
+        // Before `set_child_count` was taught to fire for directories whose
+        // `read_dir` fails, the root's stats node would never finish (`b`'s
+        // own count never arrives), so `on_dir` would never run for it.
+        let stats = root_stats.lock().unwrap().take()
+            .expect("on_dir should still fire for the root");
+        assert_eq!(stats, DirStats { size: 5, count: 1 });
+    }
+
+    #[test]
+    fn process_read_dir_can_filter_children() {
+        let td = TempDir::new("walk-test-").unwrap();
+        mkdirp(td.path().join("a"));
+        wfile(td.path().join("a/keep"), "");
+        wfile(td.path().join("a/drop"), "");
+
+        let mut builder = WalkBuilder::new(td.path());
+        builder.process_read_dir(|_, _, dents, _: &mut ()| {
+            dents.retain(|d| d.file_name() != "drop");
+        });
+        let walker = builder.build_parallel();
+        let got = walk_parallel_collect(td.path(), walker);
+        assert_eq!(got, mkpaths(&["a", "a/keep"]));
+    }
+
+    /// A `FileSystem` that wraps `OsFileSystem` but hides any entry whose
+    /// file name is `hidden`, exercising `WalkBuilder::filesystem` as an
+    /// overlay rather than a fully synthetic in-memory tree (per its own
+    /// doc comment, `Metadata`/`FileType` can't be synthesized without
+    /// backing real files).
+    #[derive(Debug)]
+    struct HidingFileSystem(OsFileSystem);
+
+    impl FileSystem for HidingFileSystem {
+        fn read_dir(
+            &self,
+            path: &Path,
+        ) -> io::Result<Box<Iterator<Item = io::Result<FileSystemEntry>>>> {
+            let it = try!(self.0.read_dir(path)).filter(|result| {
+                match *result {
+                    Ok(ref ent) => ent.path().file_name()
+                        .map_or(true, |n| n != "hidden"),
+                    Err(_) => true,
+                }
+            });
+            Ok(Box::new(it))
+        }
+
+        fn metadata(&self, path: &Path) -> io::Result<fs::Metadata> {
+            self.0.metadata(path)
+        }
+
+        fn symlink_metadata(&self, path: &Path) -> io::Result<fs::Metadata> {
+            self.0.symlink_metadata(path)
+        }
+    }
+
+    #[test]
+    fn custom_filesystem_can_hide_entries() {
+        let td = TempDir::new("walk-test-").unwrap();
+        mkdirp(td.path().join("a"));
+        wfile(td.path().join("a/visible"), "");
+        wfile(td.path().join("a/hidden"), "");
+
+        let mut builder = WalkBuilder::new(td.path());
+        builder.filesystem(Arc::new(HidingFileSystem(OsFileSystem::default())));
+        let walker = builder.build_parallel();
+        let got = walk_parallel_collect(td.path(), walker);
+        assert_eq!(got, mkpaths(&["a", "a/visible"]));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn symlink_loop_reports_error() {
+        use std::os::unix::fs::symlink;
+
+        let td = TempDir::new("walk-test-").unwrap();
+        mkdirp(td.path().join("a"));
+        symlink(td.path().join("a"), td.path().join("a/loop")).unwrap();
+
+        let mut builder = WalkBuilder::new(td.path());
+        builder.follow_links(true);
+        let walker = builder.build_parallel();
+
+        let saw_error = Arc::new(Mutex::new(false));
+        {
+            let saw_error = saw_error.clone();
+            walker.run(move |result| {
+                if result.is_err() {
+                    *saw_error.lock().unwrap() = true;
+                }
+                WalkState::Continue
+            });
+        }
+        assert!(
+            *saw_error.lock().unwrap(),
+            "expected a symlink-loop error instead of an infinite descent",
+        );
+    }
+
+    #[test]
+    fn run_buffered_default_sorts_small_walk() {
+        let td = TempDir::new("walk-test-").unwrap();
+        mkdirp(td.path().join("a"));
+        wfile(td.path().join("a/2"), "");
+        wfile(td.path().join("a/1"), "");
+
+        let walker = WalkBuilder::new(td.path()).build_parallel();
+        let prefix = td.path().to_path_buf();
+        let got: Vec<String> = walker
+            .run_buffered_default(|a, b| a.path().cmp(b.path()))
+            .map(|result| result.unwrap())
+            .map(|dent| {
+                let path = dent.path().strip_prefix(&prefix).unwrap();
+                path.to_str().unwrap().to_string()
+            })
+            .filter(|p| !p.is_empty())
+            .collect();
+        assert_eq!(got, vec![
+            normal_path("a"), normal_path("a/1"), normal_path("a/2"),
+        ]);
+    }
+
     #[test]
     fn no_ignores() {
         let td = TempDir::new("walk-test-").unwrap();
@@ -1051,4 +3248,42 @@ mod tests {
         let got = walk_collect(&root, Walk::new(&root));
         assert_eq!(got, mkpaths(&["bar"]));
     }
+
+    fn run_git(repo: &Path, args: &[&str]) {
+        let status = Command::new("git").args(args)
+            .current_dir(repo).status().unwrap();
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    #[test]
+    fn git_modified_relative_subdirectory_root() {
+        let td = TempDir::new("walk-test-").unwrap();
+        let repo = td.path();
+        mkdirp(repo.join("a"));
+        wfile(repo.join("a/unchanged"), "unchanged");
+        wfile(repo.join("a/changed"), "before");
+
+        run_git(repo, &["init", "-q"]);
+        run_git(repo, &["config", "user.email", "walk-test@example.com"]);
+        run_git(repo, &["config", "user.name", "walk-test"]);
+        run_git(repo, &["add", "."]);
+        run_git(repo, &["commit", "-q", "-m", "initial"]);
+        wfile(repo.join("a/changed"), "after");
+
+        // `root` ("a") is both relative and a subdirectory of the
+        // repository, so the git-diff paths (toplevel-relative, e.g.
+        // "a/changed") land nowhere near `ent.path()`'s own root-relative
+        // form (e.g. "a/changed" joined onto "a" would be "a/a/changed")
+        // unless both are normalized to the same base first.
+        let saved_cwd = env::current_dir().unwrap();
+        env::set_current_dir(repo).unwrap();
+        let got = {
+            let mut builder = WalkBuilder::new("a");
+            builder.git_modified(Some("HEAD"));
+            walk_collect(Path::new("a"), builder.build())
+        };
+        env::set_current_dir(&saved_cwd).unwrap();
+
+        assert_eq!(got, mkpaths(&["changed"]));
+    }
 }